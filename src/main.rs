@@ -5,21 +5,31 @@
 #[macro_use]
 extern crate lazy_static;
 
+use std::collections::VecDeque;
 use std::env;
+use std::fs;
 use std::fs::File;
-use std::io::{self, BufReader};
+use std::io::{self, BufReader, SeekFrom};
 use std::io::prelude::*;
 use std::mem::MaybeUninit;
+use std::net::{TcpListener, TcpStream};
 use std::option::Option;
-use std::path::Path;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::result::Result;
 use std::str;
 use std::string::String;
-use std::sync::mpsc::channel;
-use std::time::Duration;
-
-use notify::{Watcher, RecursiveMode, Event, EventKind, event::ModifyKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use flate2::Compression;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use notify::{Watcher, RecommendedWatcher, RecursiveMode, Event, EventKind, event::ModifyKind};
+use serde::Deserialize;
 use serde_json::json;
 use regex::bytes::Regex;
 use ureq::Agent;
@@ -29,20 +39,38 @@ use url::Url;
 
 /// ADIF records reader
 struct RecordsReader {
+	path: PathBuf,
 	file: File,
-	buffer: Vec<u8>
+	buffer: Vec<u8>,
+	/// Absolute byte offset consumed from the file so far
+	offset: u64,
+	/// `(device, inode)` of the file at the path when it was last opened, to
+	/// detect rotation that a pure length check can miss
+	identity: (u64, u64)
 }
 
 impl RecordsReader {
 	/// Read chunk size
 	const CHUNK_SIZE: usize = 256 * 1024;
 
-	/// Create new records reader
-	fn new(file: File) -> Self {
-		Self {
+	/// Create a new records reader, recording the file's identity so later
+	/// rotation can be detected even if the length check alone would miss it
+	fn new(path: impl Into<PathBuf>, file: File) -> io::Result<Self> {
+		let identity = Self::identity_of(&file)?;
+
+		Ok(Self {
+			path: path.into(),
 			file,
-			buffer: Vec::<u8>::new()
-		}
+			buffer: Vec::<u8>::new(),
+			offset: 0,
+			identity
+		})
+	}
+
+	/// `(device, inode)` pair identifying the file behind an open handle
+	fn identity_of(file: &File) -> io::Result<(u64, u64)> {
+		let meta = file.metadata()?;
+		Ok((meta.dev(), meta.ino()))
 	}
 
 	/// Length of longest chunk of complete records in the buffer
@@ -58,6 +86,38 @@ impl RecordsReader {
 		}
 	}
 
+	/// Detect both common rotation modes and resume reading from the
+	/// beginning of whatever is now at the path:
+	///
+	/// - `logrotate`'s `copytruncate` shrinks the file in place, which a
+	///   plain length check below the consumed offset catches;
+	/// - rename-then-recreate replaces the inode at the path entirely, which
+	///   a length check can miss if the new file is written past the old
+	///   offset before the next check runs, so the path is re-stat'd and
+	///   compared against the identity recorded when it was opened.
+	fn check_truncation(&mut self) -> io::Result<()> {
+		let len = self.file.metadata()?.len();
+		let rotated = match fs::metadata(&self.path) {
+			Ok(meta) => (meta.dev(), meta.ino()) != self.identity,
+			Err(_) => false
+		};
+
+		if rotated {
+			eprintln!("<5>Log file {} was replaced. Reopening from the start.", self.path.display());
+			let file = File::open(&self.path)?;
+			self.identity = Self::identity_of(&file)?;
+			self.file = file;
+			self.buffer.clear();
+			self.offset = 0;
+		} else if len < self.offset {
+			eprintln!("<5>Log file appears to have been truncated. Resuming from the start.");
+			self.buffer.clear();
+			self.offset = 0;
+			self.file.seek(SeekFrom::Start(0))?;
+		}
+
+		Ok(())
+	}
 }
 
 impl Iterator for RecordsReader {
@@ -65,6 +125,11 @@ impl Iterator for RecordsReader {
 
 	/// Read a chunk of complete ADIF records
 	fn next(&mut self) -> Option<String> {
+		self.check_truncation().unwrap_or_else(|err| {
+			eprintln!("Failed to stat log file: {err}");
+			exit(74);
+		});
+
 		self.buffer.reserve(Self::CHUNK_SIZE);
 		let tail = unsafe { MaybeUninit::slice_assume_init_mut(self.buffer.spare_capacity_mut()) };
 		let rlen = self.file.read(tail).unwrap_or_else(|err| {
@@ -73,6 +138,7 @@ impl Iterator for RecordsReader {
 		});
 
 		unsafe { self.buffer.set_len(self.buffer.len() + rlen); }
+		self.offset += rlen as u64;
 		let clen = self.complete();
 
 		if clen == 0 {
@@ -91,20 +157,415 @@ impl Iterator for RecordsReader {
 	}
 }
 
-/// Upload new records from log
-fn upload(agent: &Agent, uri: &Uri, key: &str, profile: &str, log: &mut RecordsReader) {
-	for rec in log {
-		agent.put(uri).send_json(json!({
-			"key": key,
-			"station_profile_id": profile,
-			"type": "adif",
-			"string": rec
-		})).unwrap_or_else(|err| {
-			eprintln!("<2>Failed to upload log records: {err}");
+/// Durable queue of records awaiting upload, persisted to an append-only
+/// sidecar file next to the log
+struct PendingQueue {
+	path: PathBuf,
+	file: File,
+	records: VecDeque<String>,
+	/// Sidecar entries appended since the last full compaction
+	dirty: usize
+}
+
+impl PendingQueue {
+	/// Sidecar entry tag: push a record to the back of the queue
+	const TAG_PUSH_BACK: u8 = 0;
+	/// Sidecar entry tag: push a record to the front of the queue
+	const TAG_PUSH_FRONT: u8 = 1;
+	/// Sidecar entry tag: drop the record at the front of the queue
+	const TAG_POP: u8 = 2;
+
+	/// Compact the sidecar once more than this many entries have been appended
+	/// to it since it was last compacted
+	const COMPACT_THRESHOLD: usize = 64;
+
+	/// Load a pending queue, replaying its sidecar file's append-only log of
+	/// pushes and pops, if one exists
+	fn open(log_path: &str) -> io::Result<Self> {
+		let path = PathBuf::from(format!("{log_path}.pending"));
+		let mut records = VecDeque::new();
+		let mut dirty = 0;
+
+		if let Ok(mut replay) = File::open(&path) {
+			loop {
+				let mut tag = [0u8; 1];
+
+				match replay.read_exact(&mut tag) {
+					Ok(()) => (),
+					Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+					Err(err) => return Err(err)
+				}
+
+				match tag[0] {
+					Self::TAG_POP => { records.pop_front(); },
+					Self::TAG_PUSH_BACK | Self::TAG_PUSH_FRONT => {
+						let mut len = [0u8; 8];
+						replay.read_exact(&mut len)?;
+						let mut rec = vec![0u8; u64::from_be_bytes(len) as usize];
+						replay.read_exact(&mut rec)?;
+						let rec = String::from_utf8_lossy(&rec).into_owned();
+
+						if tag[0] == Self::TAG_PUSH_FRONT {
+							records.push_front(rec);
+						} else {
+							records.push_back(rec);
+						}
+					},
+					tag => return Err(io::Error::new(io::ErrorKind::InvalidData,
+					                                  format!("unknown pending queue entry tag {tag}")))
+				}
+
+				dirty += 1;
+			}
+		}
+
+		let file = File::options().create(true).append(true).open(&path)?;
+		let mut queue = Self { path, file, records, dirty };
+
+		if queue.dirty > Self::COMPACT_THRESHOLD {
+			queue.compact()?;
+		}
+
+		Ok(queue)
+	}
+
+	/// Rewrite the sidecar file to hold just the current records, discarding
+	/// the history of pushes and pops that produced them
+	fn compact(&mut self) -> io::Result<()> {
+		let mut file = File::create(&self.path)?;
+
+		for rec in &self.records {
+			file.write_all(&[Self::TAG_PUSH_BACK])?;
+			file.write_all(&(rec.len() as u64).to_be_bytes())?;
+			file.write_all(rec.as_bytes())?;
+		}
+
+		file.sync_all()?;
+		self.file = File::options().append(true).open(&self.path)?;
+		self.dirty = 0;
+
+		Ok(())
+	}
+
+	/// Append a single sidecar entry, compacting first if enough stale
+	/// history has piled up to be worth rewriting
+	fn append(&mut self, tag: u8, rec: Option<&str>) -> io::Result<()> {
+		if self.dirty > Self::COMPACT_THRESHOLD {
+			self.compact()?;
+		}
+
+		self.file.write_all(&[tag])?;
+
+		if let Some(rec) = rec {
+			self.file.write_all(&(rec.len() as u64).to_be_bytes())?;
+			self.file.write_all(rec.as_bytes())?;
+		}
+
+		self.file.sync_all()?;
+		self.dirty += 1;
+
+		Ok(())
+	}
+
+	/// Queue a whole batch of newly read records, syncing only once
+	fn extend(&mut self, recs: impl IntoIterator<Item = String>) -> io::Result<()> {
+		if self.dirty > Self::COMPACT_THRESHOLD {
+			self.compact()?;
+		}
+
+		let mut appended = 0;
+
+		for rec in recs {
+			self.file.write_all(&[Self::TAG_PUSH_BACK])?;
+			self.file.write_all(&(rec.len() as u64).to_be_bytes())?;
+			self.file.write_all(rec.as_bytes())?;
+			self.records.push_back(rec);
+			appended += 1;
+		}
+
+		if appended > 0 {
+			self.file.sync_all()?;
+			self.dirty += appended;
+		}
+
+		Ok(())
+	}
+
+	/// Re-queue a record at the front after a failed upload
+	fn push_front(&mut self, rec: String) -> io::Result<()> {
+		self.append(Self::TAG_PUSH_FRONT, Some(&rec))?;
+		self.records.push_front(rec);
+		Ok(())
+	}
+
+	/// Take the next record due for upload, appending a pop marker so a
+	/// restart does not replay it
+	fn pop_front(&mut self) -> io::Result<Option<String>> {
+		let rec = self.records.pop_front();
+
+		if rec.is_some() {
+			self.append(Self::TAG_POP, None)?;
+		}
+
+		Ok(rec)
+	}
+}
+
+/// Exponential backoff between retries of a failed upload
+struct Backoff {
+	base: Duration,
+	cap: Duration,
+	current: Duration
+}
+
+impl Backoff {
+	/// Create a new backoff with the given base delay (tranquility) and cap
+	fn new(base: Duration, cap: Duration) -> Self {
+		Self { base, cap, current: base }
+	}
+
+	/// Sleep for the current delay, then double it up to the cap
+	fn wait(&mut self) {
+		eprintln!("<6>Retrying in {:.1}s.", self.current.as_secs_f64());
+		sleep(self.current);
+		self.current = self.current.saturating_mul(2).min(self.cap);
+	}
+
+	/// Reset the delay to the base after a successful upload
+	fn reset(&mut self) {
+		self.current = self.base;
+	}
+}
+
+/// Whether a failed upload is transient and should be retried
+fn is_recoverable(err: &ureq::Error) -> bool {
+	match err {
+		// Server errors and request timeouts are assumed transient
+		ureq::Error::StatusCode(code) => *code >= 500 || *code == 408,
+		// Anything else is a connection or protocol level failure
+		_ => true
+	}
+}
+
+/// Request body compression scheme
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+	Identity,
+	Deflate,
+	Gzip
+}
+
+impl Encoding {
+	/// Parse a `Content-Encoding` token, or `"none"` for no compression
+	fn parse(token: &str) -> Option<Self> {
+		match token {
+			"none" => Some(Self::Identity),
+			"deflate" => Some(Self::Deflate),
+			"gzip" => Some(Self::Gzip),
+			_ => None
+		}
+	}
+
+	/// Corresponding `Content-Encoding` header value
+	fn header(self) -> Option<&'static str> {
+		match self {
+			Self::Identity => None,
+			Self::Deflate => Some("deflate"),
+			Self::Gzip => Some("gzip")
+		}
+	}
+
+	/// Compress a payload with this encoding
+	fn encode(self, data: &[u8]) -> io::Result<Vec<u8>> {
+		match self {
+			Self::Identity => Ok(data.to_vec()),
+			Self::Deflate => {
+				let mut enc = DeflateEncoder::new(Vec::new(), Compression::default());
+				enc.write_all(data)?;
+				enc.finish()
+			},
+			Self::Gzip => {
+				let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+				enc.write_all(data)?;
+				enc.finish()
+			}
+		}
+	}
+}
+
+/// Total ADIF records successfully uploaded
+static RECORDS_UPLOADED_TOTAL: AtomicU64 = AtomicU64::new(0);
+
+/// Total bytes of ADIF data successfully uploaded
+static BYTES_UPLOADED_TOTAL: AtomicU64 = AtomicU64::new(0);
+
+/// Total failed upload attempts, including ones later retried
+static UPLOAD_FAILURES_TOTAL: AtomicU64 = AtomicU64::new(0);
+
+/// Total file system events observed on the watched logs of all targets
+static FILE_EVENTS_TOTAL: AtomicU64 = AtomicU64::new(0);
+
+/// Unix timestamp of the last successful upload
+static LAST_UPLOAD_TIMESTAMP_SECONDS: AtomicU64 = AtomicU64::new(0);
+
+/// Seconds since the Unix epoch, for the last-upload gauge
+fn now_secs() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Render the global counters in Prometheus text exposition format
+fn render_metrics() -> String {
+	format!(
+		"# TYPE adifwatch_records_uploaded_total counter\n\
+		 adifwatch_records_uploaded_total {}\n\
+		 # TYPE adifwatch_bytes_uploaded_total counter\n\
+		 adifwatch_bytes_uploaded_total {}\n\
+		 # TYPE adifwatch_upload_failures_total counter\n\
+		 adifwatch_upload_failures_total {}\n\
+		 # TYPE adifwatch_file_events_total counter\n\
+		 adifwatch_file_events_total {}\n\
+		 # TYPE adifwatch_last_upload_timestamp_seconds gauge\n\
+		 adifwatch_last_upload_timestamp_seconds {}\n",
+		RECORDS_UPLOADED_TOTAL.load(Ordering::Relaxed),
+		BYTES_UPLOADED_TOTAL.load(Ordering::Relaxed),
+		UPLOAD_FAILURES_TOTAL.load(Ordering::Relaxed),
+		FILE_EVENTS_TOTAL.load(Ordering::Relaxed),
+		LAST_UPLOAD_TIMESTAMP_SECONDS.load(Ordering::Relaxed)
+	)
+}
+
+/// How long a metrics client gets to send its request line, or to drain the
+/// response, before the connection is dropped
+const METRICS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serve a single `GET /metrics` request in Prometheus exposition format
+fn serve_metrics(stream: TcpStream) {
+	let _ = stream.set_read_timeout(Some(METRICS_TIMEOUT));
+	let _ = stream.set_write_timeout(Some(METRICS_TIMEOUT));
+
+	let mut reader = BufReader::new(&stream);
+	let mut request_line = String::new();
+
+	if reader.read_line(&mut request_line).is_err() {
+		return;
+	}
+
+	let mut stream = reader.into_inner();
+	let body = if request_line.starts_with("GET /metrics ") {
+		render_metrics()
+	} else {
+		"Not Found\n".to_string()
+	};
+
+	let status = if request_line.starts_with("GET /metrics ") { "200 OK" } else { "404 Not Found" };
+	let _ = write!(stream, "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+	                        Content-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len());
+}
+
+/// Spawn a background thread serving Prometheus metrics over plain HTTP
+///
+/// Each connection is handled on its own thread: a client that opens a
+/// connection and never sends a request line (a port probe, a dead peer)
+/// must not be able to starve the accept loop and wedge the whole endpoint.
+fn spawn_metrics_server(addr: &str) {
+	let listener = TcpListener::bind(addr).unwrap_or_else(|err| {
+		eprintln!("<2>Failed to bind metrics listener on {addr}: {err}");
+		exit(71);
+	});
+
+	eprintln!("<6>Serving Prometheus metrics on http://{addr}/metrics");
+
+	thread::spawn(move || {
+		for stream in listener.incoming() {
+			match stream {
+				Ok(stream) => { thread::spawn(move || serve_metrics(stream)); },
+				Err(err) => eprintln!("<3>Metrics listener error: {err}")
+			}
+		}
+	});
+}
+
+/// Upload target and durable upload state
+struct Uploader {
+	agent: Agent,
+	uri: Uri,
+	key: String,
+	profile: String,
+	queue: PendingQueue,
+	backoff: Backoff,
+	encoding: Encoding
+}
+
+impl Uploader {
+	/// Upload new records from log, retrying transient failures durably.
+	/// Returns `false` if this target has hit an unrecoverable error and
+	/// should stop, leaving the rest of the process running.
+	fn upload(&mut self, log: &mut RecordsReader) -> bool {
+		self.queue.extend(log).unwrap_or_else(|err| {
+			eprintln!("<2>Failed to persist pending upload queue: {err}");
 			exit(74);
 		});
 
-		eprintln!("<7>Uploaded {} bytes of log data.", rec.len());
+		while let Some(rec) = self.queue.pop_front().unwrap_or_else(|err| {
+			eprintln!("<2>Failed to persist pending upload queue: {err}");
+			exit(74);
+		}) {
+			let body = serde_json::to_vec(&json!({
+				"key": self.key,
+				"station_profile_id": self.profile,
+				"type": "adif",
+				"string": rec
+			})).unwrap();
+
+			let (payload, encoding) = match self.encoding.encode(&body) {
+				Ok(payload) => (payload, self.encoding),
+				Err(err) => {
+					eprintln!("<3>Failed to compress upload body, sending uncompressed: {err}");
+					(body.clone(), Encoding::Identity)
+				}
+			};
+
+			let req = self.agent.put(&self.uri).header("Content-Type", "application/json");
+			let result = match encoding.header() {
+				Some(enc) => req.header("Content-Encoding", enc).send(&payload),
+				None => req.send(&payload)
+			};
+
+			match result {
+				Ok(_) => {
+					eprintln!("<7>Uploaded {} bytes of log data.", rec.len());
+					self.backoff.reset();
+					RECORDS_UPLOADED_TOTAL.fetch_add(1, Ordering::Relaxed);
+					BYTES_UPLOADED_TOTAL.fetch_add(rec.len() as u64, Ordering::Relaxed);
+					LAST_UPLOAD_TIMESTAMP_SECONDS.store(now_secs(), Ordering::Relaxed);
+				},
+				Err(ureq::Error::StatusCode(415)) if self.encoding != Encoding::Identity => {
+					eprintln!("<5>CloudLog rejected compressed upload. Falling back to uncompressed.");
+					self.encoding = Encoding::Identity;
+					UPLOAD_FAILURES_TOTAL.fetch_add(1, Ordering::Relaxed);
+					self.queue.push_front(rec).unwrap_or_else(|err| {
+						eprintln!("<2>Failed to persist pending upload queue: {err}");
+						exit(74);
+					});
+				},
+				Err(err) if is_recoverable(&err) => {
+					eprintln!("<4>Failed to upload log records, will retry: {err}");
+					UPLOAD_FAILURES_TOTAL.fetch_add(1, Ordering::Relaxed);
+					self.queue.push_front(rec).unwrap_or_else(|err| {
+						eprintln!("<2>Failed to persist pending upload queue: {err}");
+						exit(74);
+					});
+					self.backoff.wait();
+				},
+				Err(err) => {
+					eprintln!("<2>Upload rejected, giving up on this target: {err}");
+					UPLOAD_FAILURES_TOTAL.fetch_add(1, Ordering::Relaxed);
+					return false;
+				}
+			}
+		}
+
+		true
 	}
 }
 
@@ -118,61 +579,285 @@ fn api_uri(base: &str) -> Result<Uri, url::ParseError> {
 	Ok(Url::parse(base)?.join("api/qso")?.as_str().parse::<Uri>().unwrap())
 }
 
-fn main() -> io::Result<()> {
-	let mut args = env::args();
+/// A single upload target: one ADIF log mirrored to one CloudLog instance
+#[derive(Deserialize)]
+struct TargetConfig {
+	base_url: String,
+	api_key_path: String,
+	profile: String,
+	log_path: String
+}
 
-	if args.len() <= 1 {
-		eprintln!("Usage: {} [base URL] [API key file] [profile ID] [ADIF log file]",
-		          args.next().unwrap());
+/// Multi-target configuration file
+#[derive(Deserialize)]
+struct Config {
+	tranquility: Option<f64>,
+	compression: Option<String>,
+	metrics_addr: Option<String>,
+	target: Vec<TargetConfig>
+}
+
+/// Load and parse a TOML config file
+fn load_config(path: &str) -> io::Result<Config> {
+	let text = std::fs::read_to_string(path)?;
+	toml::from_str(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Remove a `--flag value` pair from the argument list, if present
+fn take_flag_value(args: &mut Vec<String>, flag: &str) -> Option<String> {
+	let idx = args.iter().position(|arg| arg == flag)?;
+
+	if idx + 1 >= args.len() {
+		eprintln!("{flag} requires a value");
 		exit(64);
 	}
 
-	let uri = api_uri(&args.nth(1).unwrap_or_else(|| {
-		eprintln!("Missing CloudLog base URL");
-		exit(64);
-	})).unwrap_or_else(|err| {
-		eprintln!("Failed to construct QSO API URL: {err}");
-		exit(64);
-	});
+	args.remove(idx);
+	Some(args.remove(idx))
+}
 
-	let key = read_key(&args.next().unwrap_or_else(|| {
-		eprintln!("Missing API key file path");
+/// Parse a tranquility value given in seconds
+fn parse_tranquility(secs: f64) -> Duration {
+	Duration::try_from_secs_f64(secs).unwrap_or_else(|err| {
+		eprintln!("Invalid tranquility value: {err}");
 		exit(64);
-	})).unwrap_or_else(|err| {
-		eprintln!("Failed to read API key: {err}");
-		exit(66);
-	});
+	})
+}
 
-	let profile = args.next().unwrap_or_else(|| {
-		eprintln!("Missing station profile ID");
+/// Parse a `Content-Encoding` compression scheme
+fn parse_compression(token: &str) -> Encoding {
+	Encoding::parse(token).unwrap_or_else(|| {
+		eprintln!("Invalid compression scheme {token:?}, expected \
+		           \"none\", \"deflate\" or \"gzip\"");
 		exit(64);
-	});
+	})
+}
 
-	let log_path = args.next().unwrap_or_else(|| {
-		eprintln!("Missing log file path");
-		exit(64);
-	});
+/// Debounce window to let a burst of rename/remove/create events settle
+/// before trying to reopen a rotated log
+const REOPEN_DEBOUNCE: Duration = Duration::from_millis(200);
 
-	let mut log = RecordsReader::new(File::open(&log_path).unwrap_or_else(|err| {
-		eprintln!("Failed to open log file: {err}");
-		exit(66);
-	}));
+/// How long to wait for a rotated log to reappear before giving up
+const REOPEN_TIMEOUT: Duration = Duration::from_secs(30);
 
-	let (tx, rx) = channel();
-	let mut watcher = notify::recommended_watcher(tx).unwrap_or_else(|err| {
-		eprintln!("Failed to set up file watcher: {err}");
+/// Set up a `notify` watch on `log_path`
+fn watch(log_path: &str, tx: &Sender<notify::Result<Event>>) -> RecommendedWatcher {
+	let tx = tx.clone();
+	let mut watcher = notify::recommended_watcher(move |ev| {
+		let _ = tx.send(ev);
+	}).unwrap_or_else(|err| {
+		eprintln!("Failed to set up file watcher for {log_path}: {err}");
 		exit(71);
 	});
 
-	watcher.watch(Path::new(&log_path), RecursiveMode::NonRecursive).unwrap_or_else(|err| {
-		eprintln!("Unable to watch log file for changes: {err}");
+	watcher.watch(Path::new(log_path), RecursiveMode::NonRecursive).unwrap_or_else(|err| {
+		eprintln!("Unable to watch log file {log_path}: {err}");
 		exit(71);
 	});
 
+	watcher
+}
+
+/// Re-open a rotated log and re-establish its filesystem watch, retrying
+/// until it reappears or [`REOPEN_TIMEOUT`] elapses, rather than bailing out.
+/// Returns `None` once the deadline passes, so the caller can give up on just
+/// this target instead of taking the whole process down with it.
+fn reopen(log_path: &str, tx: &Sender<notify::Result<Event>>) -> Option<(RecordsReader, RecommendedWatcher)> {
+	let deadline = Instant::now() + REOPEN_TIMEOUT;
+	sleep(REOPEN_DEBOUNCE);
+
+	loop {
+		match File::open(log_path).and_then(|file| RecordsReader::new(log_path, file)) {
+			Ok(reader) => return Some((reader, watch(log_path, tx))),
+			Err(err) if Instant::now() < deadline => {
+				eprintln!("<5>Log file {log_path} not available yet, retrying: {err}");
+				sleep(REOPEN_DEBOUNCE);
+			},
+			Err(_) => return None
+		}
+	}
+}
+
+/// Run the full lifecycle of a single upload target: initial full-log
+/// upload, then react to filesystem events on its own thread until the log
+/// is gone for good or the watch itself errors out. Targets are isolated
+/// from one another this way so that one stuck or misconfigured target
+/// cannot stall uploads or rotation handling for the rest.
+fn run_target(target: TargetConfig, agent: Agent, tranquility: Duration, compression: Encoding) {
+	let uri = match api_uri(&target.base_url) {
+		Ok(uri) => uri,
+		Err(err) => {
+			eprintln!("<2>Failed to construct QSO API URL for {}: {err}. Giving up on this target.",
+			           target.base_url);
+			return;
+		}
+	};
+
+	let key = match read_key(&target.api_key_path) {
+		Ok(key) => key,
+		Err(err) => {
+			eprintln!("<2>Failed to read API key from {}: {err}. Giving up on this target.",
+			           target.api_key_path);
+			return;
+		}
+	};
+
+	let queue = match PendingQueue::open(&target.log_path) {
+		Ok(queue) => queue,
+		Err(err) => {
+			eprintln!("<2>Failed to load pending upload queue for {}: {err}. Giving up on this target.",
+			           target.log_path);
+			return;
+		}
+	};
+
+	let mut log = match File::open(&target.log_path).and_then(|file| RecordsReader::new(&target.log_path, file)) {
+		Ok(log) => log,
+		Err(err) => {
+			eprintln!("<2>Failed to open log file {}: {err}. Giving up on this target.", target.log_path);
+			return;
+		}
+	};
+
+	/// Cap retry delay at five minutes
+	const BACKOFF_CAP: Duration = Duration::from_secs(300);
+
+	let mut uploader = Uploader {
+		agent,
+		uri,
+		key,
+		profile: target.profile.clone(),
+		queue,
+		backoff: Backoff::new(tranquility, BACKOFF_CAP),
+		encoding: compression
+	};
+
+	let (tx, rx) = channel();
+	let mut _watcher = watch(&target.log_path, &tx);
+
+	eprintln!("<6>Performing initial full log upload for {}.", target.log_path);
+
+	if !uploader.upload(&mut log) {
+		return;
+	}
+
+	for ev in rx {
+		#[cfg(debug_assertions)]
+		eprintln!("<7>Log file event for {}: {ev:?}", target.log_path);
+
+		FILE_EVENTS_TOTAL.fetch_add(1, Ordering::Relaxed);
+
+		match ev {
+			Ok(Event { kind: EventKind::Modify(ModifyKind::Data(_)), paths: _, attrs: _ }) => {
+				eprintln!("<6>Change detected in log file. Checking for updates.");
+
+				if !uploader.upload(&mut log) {
+					return;
+				}
+			},
+			Ok(Event { kind: EventKind::Remove(_), paths: _, attrs: _ })
+			| Ok(Event { kind: EventKind::Modify(ModifyKind::Name(_)), paths: _, attrs: _ })
+			| Ok(Event { kind: EventKind::Create(_), paths: _, attrs: _ }) => {
+				eprintln!("<5>Log file {} rotated away. Reopening.", target.log_path);
+
+				match reopen(&target.log_path, &tx) {
+					Some((reader, watcher)) => {
+						log = reader;
+						_watcher = watcher;
+
+						if !uploader.upload(&mut log) {
+							return;
+						}
+					},
+					None => {
+						eprintln!("<2>Log file {} did not reappear. Giving up on this target.",
+						           target.log_path);
+						return;
+					}
+				}
+			},
+			Ok(_) => { },
+			Err(err) => {
+				eprintln!("<2>Error detected while watching {}: {err}", target.log_path);
+				return;
+			}
+		}
+	}
+}
+
+fn main() -> io::Result<()> {
+	let mut raw_args: Vec<String> = env::args().collect();
+	let prog = raw_args[0].clone();
+
+	let metrics_addr_flag = take_flag_value(&mut raw_args, "--metrics-addr");
+	let config_path = take_flag_value(&mut raw_args, "--config");
+
+	if config_path.is_none() && raw_args.len() <= 1 {
+		eprintln!("Usage: {prog} [base URL] [API key file] [profile ID] [ADIF log file] \
+		           [tranquility] [compression] [--metrics-addr host:port]\n\
+		       or: {prog} --config <config.toml> [--metrics-addr host:port]");
+		exit(64);
+	}
+
+	/// Default tranquility (initial retry delay) of two seconds
+	const TRANQUILITY: Duration = Duration::from_secs(2);
+
+	let (targets, tranquility, compression, metrics_addr) = if let Some(path) = config_path {
+		let config = load_config(&path).unwrap_or_else(|err| {
+			eprintln!("Failed to load config {path}: {err}");
+			exit(78);
+		});
+
+		if config.target.is_empty() {
+			eprintln!("Config {path} does not define any [[target]]");
+			exit(78);
+		}
+
+		let tranquility = config.tranquility.map(parse_tranquility).unwrap_or(TRANQUILITY);
+		let compression = config.compression.as_deref().map(parse_compression)
+			.unwrap_or(Encoding::Identity);
+		let metrics_addr = metrics_addr_flag.or(config.metrics_addr);
+
+		(config.target, tranquility, compression, metrics_addr)
+	} else {
+		let mut args = raw_args.into_iter();
+		args.next();
+
+		let base_url = args.next().unwrap_or_else(|| {
+			eprintln!("Missing CloudLog base URL");
+			exit(64);
+		});
+
+		let api_key_path = args.next().unwrap_or_else(|| {
+			eprintln!("Missing API key file path");
+			exit(64);
+		});
+
+		let profile = args.next().unwrap_or_else(|| {
+			eprintln!("Missing station profile ID");
+			exit(64);
+		});
+
+		let log_path = args.next().unwrap_or_else(|| {
+			eprintln!("Missing log file path");
+			exit(64);
+		});
+
+		let tranquility = args.next().map(|arg| parse_tranquility(arg.parse().unwrap_or_else(|err| {
+			eprintln!("Invalid tranquility value: {err}");
+			exit(64);
+		}))).unwrap_or(TRANQUILITY);
+
+		let compression = args.next().as_deref().map(parse_compression).unwrap_or(Encoding::Identity);
+
+		(vec![TargetConfig { base_url, api_key_path, profile, log_path }],
+		 tranquility, compression, metrics_addr_flag)
+	};
+
 	/// Default time‐out of one minute
 	const TIMEOUT: Duration = Duration::from_secs(60);
 
-	let agent = Agent::config_builder()
+	let agent: Agent = Agent::config_builder()
 		.https_only(true)
 		// Use platform root certificates
 		.tls_config(TlsConfig::builder().root_certs(RootCerts::PlatformVerifier).build())
@@ -194,29 +879,114 @@ fn main() -> io::Result<()> {
 		.timeout_recv_body(Some(TIMEOUT))
 		.build()
 		.into();
-	eprintln!("<6>Performing initial full log upload.");
-	upload(&agent, &uri, &key, &profile, &mut log);
 
-	for ev in rx {
-		#[cfg(debug_assertions)]
-		eprintln!("<7>Log file event: {ev:?}");
+	if let Some(addr) = metrics_addr {
+		spawn_metrics_server(&addr);
+	}
 
-		match ev {
-			Ok(Event { kind: EventKind::Modify(ModifyKind::Data(_)), paths: _, attrs: _ }) => {
-				eprintln!("<6>Change detected in log file. Checking for updates.");
-				upload(&agent, &uri, &key, &profile, &mut log);
-			},
-			Ok(Event { kind: EventKind::Remove(_), paths: _, attrs: _ }) => {
-				eprintln!("<2>Log file has been removed. Bailing out.");
-				exit(74);
-			},
-			Ok(_) => { },
-			Err(err) => {
-				eprintln!("<2>Error detected while watching for file changes: {err}");
-				exit(71);
-			}
-		}
+	let handles: Vec<_> = targets.into_iter().map(|target| {
+		let agent = agent.clone();
+		thread::spawn(move || run_target(target, agent, tranquility, compression))
+	}).collect();
+
+	for handle in handles {
+		let _ = handle.join();
 	}
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Build a path for a throwaway log file under the system temp
+	/// directory, unique to this test run
+	fn temp_log_path(name: &str) -> String {
+		let pid = std::process::id();
+		let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+		env::temp_dir().join(format!("adifwatch-test-{name}-{pid}-{nanos}.log"))
+			.to_str().unwrap().to_owned()
+	}
+
+	#[test]
+	fn pending_queue_round_trips_through_sidecar() {
+		let log_path = temp_log_path("pending-roundtrip");
+		let sidecar_path = format!("{log_path}.pending");
+
+		{
+			let mut queue = PendingQueue::open(&log_path).unwrap();
+			queue.extend(["one".to_owned(), "two".to_owned(), "three".to_owned()]).unwrap();
+			assert_eq!(queue.pop_front().unwrap().as_deref(), Some("one"));
+			queue.push_front("zero".to_owned()).unwrap();
+		}
+
+		// Reopening must replay the sidecar's pushes and pops faithfully
+		let mut reopened = PendingQueue::open(&log_path).unwrap();
+		assert_eq!(reopened.pop_front().unwrap().as_deref(), Some("zero"));
+		assert_eq!(reopened.pop_front().unwrap().as_deref(), Some("two"));
+		assert_eq!(reopened.pop_front().unwrap().as_deref(), Some("three"));
+		assert_eq!(reopened.pop_front().unwrap(), None);
+
+		let _ = std::fs::remove_file(&sidecar_path);
+	}
+
+	#[test]
+	fn pending_queue_compacts_away_stale_history() {
+		let log_path = temp_log_path("pending-compact");
+		let sidecar_path = format!("{log_path}.pending");
+
+		let mut queue = PendingQueue::open(&log_path).unwrap();
+
+		for i in 0..(PendingQueue::COMPACT_THRESHOLD * 2) {
+			queue.extend([format!("rec{i}")]).unwrap();
+			queue.pop_front().unwrap();
+		}
+
+		// Every record was pushed then immediately popped, so nothing should
+		// remain once compaction has rewritten the sidecar down to its live
+		// records
+		assert_eq!(queue.records.len(), 0);
+		assert!(queue.dirty <= PendingQueue::COMPACT_THRESHOLD);
+
+		let _ = std::fs::remove_file(&sidecar_path);
+	}
+
+	#[test]
+	fn records_reader_detects_in_place_truncation_by_length() {
+		let log_path = temp_log_path("truncate-length");
+		fs::write(&log_path, b"0123456789").unwrap();
+
+		let mut reader = RecordsReader::new(&log_path, File::open(&log_path).unwrap()).unwrap();
+		reader.offset = 10;
+
+		// Shrink the file without replacing its inode, as copytruncate does
+		let file = fs::OpenOptions::new().write(true).open(&log_path).unwrap();
+		file.set_len(4).unwrap();
+
+		reader.check_truncation().unwrap();
+		assert_eq!(reader.offset, 0);
+
+		let _ = fs::remove_file(&log_path);
+	}
+
+	#[test]
+	fn records_reader_detects_rotation_by_identity_even_when_grown_past_offset() {
+		let log_path = temp_log_path("truncate-identity");
+		fs::write(&log_path, b"0123456789").unwrap();
+
+		let mut reader = RecordsReader::new(&log_path, File::open(&log_path).unwrap()).unwrap();
+		reader.offset = 10;
+
+		// Replace the file at the path with a new inode and grow it past the
+		// old offset, simulating rename-then-recreate rotation racing a long
+		// poll interval: a pure length check would miss this
+		fs::remove_file(&log_path).unwrap();
+		fs::write(&log_path, b"0123456789012345678901234567890123456789").unwrap();
+
+		reader.check_truncation().unwrap();
+		assert_eq!(reader.offset, 0);
+
+		let _ = fs::remove_file(&log_path);
+	}
+}